@@ -1,5 +1,9 @@
+extern crate byteorder;
 extern crate libusb;
+extern crate serialport;
 
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use std::fmt;
 use std::fs;
 use std::io;
 use std::net;
@@ -7,6 +11,89 @@ use std::path;
 use std::time::Duration;
 use std::vec::Vec;
 
+/// Errors produced by the device backends.
+///
+/// Mirrors the error surface of the ippusb connector: each variant names the
+/// USB/IO operation that failed rather than collapsing everything into a
+/// single opaque string, so callers can tell a missing device apart from a
+/// transfer that simply timed out.
+#[derive(Debug)]
+pub enum Error {
+    /// Failed to enumerate the devices attached to a `libusb::Context`.
+    DeviceList(libusb::Error),
+    /// Failed to open a handle to a matched device.
+    OpenDevice(libusb::Error),
+    /// Failed to claim the printer's USB interface.
+    ClaimInterface(libusb::Error),
+    /// Failed to set the device's active configuration.
+    SetActiveConfig(libusb::Error),
+    /// Failed to set the interface's alternate setting.
+    SetAlternateSetting(libusb::Error),
+    /// No device matching the requested class/VID/PID could be found.
+    NoDevice,
+    /// The matched device has no OUT endpoint.
+    NoWriteEndpoint,
+    /// The matched device has no usable bulk IN endpoint.
+    NoReadEndpoint,
+    /// Failed to detach the kernel driver bound to the printer's interface.
+    DetachKernelDriver(libusb::Error),
+    /// An underlying I/O error.
+    Io(io::Error),
+    /// Any other `libusb` failure (transfers, resets, string descriptors, ...).
+    Usb(libusb::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::DeviceList(ref err) => write!(f, "failed to list USB devices: {}", err),
+            Error::OpenDevice(ref err) => write!(f, "failed to open USB device: {}", err),
+            Error::ClaimInterface(ref err) => write!(f, "failed to claim USB interface: {}", err),
+            Error::SetActiveConfig(ref err) => write!(f, "failed to set active configuration: {}", err),
+            Error::SetAlternateSetting(ref err) => write!(f, "failed to set alternate setting: {}", err),
+            Error::NoDevice => write!(f, "no matching printer device found"),
+            Error::NoWriteEndpoint => write!(f, "matched device has no writable endpoint"),
+            Error::NoReadEndpoint => write!(f, "matched device has no readable bulk endpoint"),
+            Error::DetachKernelDriver(ref err) => write!(f, "failed to detach kernel driver: {}", err),
+            Error::Io(ref err) => write!(f, "I/O error: {}", err),
+            Error::Usb(ref err) => write!(f, "USB error: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Error::DeviceList(ref err) => Some(err),
+            Error::OpenDevice(ref err) => Some(err),
+            Error::ClaimInterface(ref err) => Some(err),
+            Error::SetActiveConfig(ref err) => Some(err),
+            Error::SetAlternateSetting(ref err) => Some(err),
+            Error::NoDevice => None,
+            Error::NoWriteEndpoint => None,
+            Error::NoReadEndpoint => None,
+            Error::DetachKernelDriver(ref err) => Some(err),
+            Error::Io(ref err) => Some(err),
+            Error::Usb(ref err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Error {
+        Error::Io(err)
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(err: Error) -> io::Error {
+        match err {
+            Error::Io(err) => err,
+            other => io::Error::new(io::ErrorKind::Other, other.to_string()),
+        }
+    }
+}
+
 #[derive(Debug)]
 struct Endpoint {
     config: u8,
@@ -20,23 +107,74 @@ pub struct Usb<'a> {
     _product_id: Option<u16>,
     device_handle: Option<libusb::DeviceHandle<'a>>,
     write_endpoint: Option<Endpoint>,
+    read_endpoint: Option<Endpoint>,
+    /// Whether to detach a bound kernel driver (e.g. usblp) before claiming
+    /// the interface, and re-attach it once we're done with the device.
+    auto_detach: bool,
+    detached_ifaces: Vec<u8>,
     stream: Vec<u8>
 }
 
-fn find_print_endpoint(context: &mut libusb::Context) -> Option<(Endpoint, u16, u16)> {
-    match find_print_device(context) {
-        Some((device, device_desc)) => {
-            match find_write_endpoint(device, device_desc) {
-                Some((endpoint, vendor_id, product_id)) => Some((endpoint, vendor_id, product_id)),
-                None => None
-            }
-        },
-        None => None
+/// Which real-time status the `DLE EOT n` command should ask the printer for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusKind {
+    Printer = 1,
+    OfflineCause = 2,
+    ErrorCause = 3,
+    PaperSensor = 4,
+}
+
+/// The decoded bits of a real-time status response.
+///
+/// Field meaning depends on the `StatusKind` that was queried; fields that
+/// don't apply to the requested kind are left at their default (`false`).
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrinterStatus {
+    pub drawer_kick_high: bool,
+    pub offline: bool,
+    pub cover_open: bool,
+    pub paper_fed_by_button: bool,
+    pub waiting_for_recovery: bool,
+    pub auto_cutter_error: bool,
+    pub paper_near_end: bool,
+    pub paper_end: bool,
+    pub error_occurred: bool,
+    pub unrecoverable_error: bool,
+}
+
+impl PrinterStatus {
+    fn decode(kind: StatusKind, byte: u8) -> PrinterStatus {
+        let mut status = PrinterStatus::default();
+
+        match kind {
+            StatusKind::Printer => {
+                status.drawer_kick_high = byte & 0b0000_0100 != 0;
+                status.offline = byte & 0b0000_1000 != 0;
+                status.waiting_for_recovery = byte & 0b0010_0000 != 0;
+            },
+            StatusKind::OfflineCause => {
+                status.cover_open = byte & 0b0000_0100 != 0;
+                status.paper_fed_by_button = byte & 0b0000_1000 != 0;
+                status.paper_end = byte & 0b0010_0000 != 0;
+                status.error_occurred = byte & 0b0100_0000 != 0;
+            },
+            StatusKind::ErrorCause => {
+                status.auto_cutter_error = byte & 0b0000_1000 != 0;
+                status.unrecoverable_error = byte & 0b0010_0000 != 0;
+                status.waiting_for_recovery = byte & 0b0100_0000 != 0;
+            },
+            StatusKind::PaperSensor => {
+                status.paper_near_end = byte & 0b0000_1100 != 0;
+                status.paper_end = byte & 0b0110_0000 != 0;
+            },
+        }
+
+        status
     }
 }
 
-fn find_print_device(context: &mut libusb::Context) -> Option<(libusb::Device, libusb::DeviceDescriptor)> {
-    for device in context.devices().unwrap().iter() {
+fn find_print_device(context: &mut libusb::Context) -> Result<Option<(libusb::Device, libusb::DeviceDescriptor)>, Error> {
+    for device in context.devices().map_err(Error::DeviceList)?.iter() {
         let device_desc = match device.device_descriptor() {
             Ok(d) => d,
             Err(_) => continue
@@ -51,17 +189,17 @@ fn find_print_device(context: &mut libusb::Context) -> Option<(libusb::Device, l
             for interface in config_desc.interfaces() {
                 for interface_desc in interface.descriptors() {
                     if interface_desc.class_code() == 7 {
-                        return Some((device, device_desc));
+                        return Ok(Some((device, device_desc)));
                     }
                 }
             }
         }
     }
 
-    None
+    Ok(None)
 }
 
-fn find_write_endpoint(device: libusb::Device, device_desc: libusb::DeviceDescriptor) -> Option<(Endpoint, u16, u16)> {
+fn find_write_endpoint(device: &libusb::Device, device_desc: &libusb::DeviceDescriptor) -> Option<(Endpoint, u16, u16)> {
     for n in 0..device_desc.num_configurations() {
         let config_desc = match device.config_descriptor(n) {
             Ok(c) => c,
@@ -71,9 +209,7 @@ fn find_write_endpoint(device: libusb::Device, device_desc: libusb::DeviceDescri
         for interface in config_desc.interfaces() {
             for interface_desc in interface.descriptors() {
                 for endpoint_desc in interface_desc.endpoint_descriptors() {
-                    println!("endpoint: {:?} {:?}", endpoint_desc.direction(), endpoint_desc.transfer_type());
                     if endpoint_desc.direction() == libusb::Direction::Out {
-                        println!("find writeable endpoint: {:?}", endpoint_desc.address());
                         return Some((
                             Endpoint {
                                 config: config_desc.number(),
@@ -93,37 +229,208 @@ fn find_write_endpoint(device: libusb::Device, device_desc: libusb::DeviceDescri
     None
 }
 
-fn configure_endpoint(handle: &mut libusb::DeviceHandle, endpoint: &Endpoint) -> libusb::Result<()> {
-    try!(handle.set_active_configuration(endpoint.config));
-    try!(handle.claim_interface(endpoint.iface));
-    try!(handle.set_alternate_setting(endpoint.iface, endpoint.setting));
+fn find_read_endpoint(device: &libusb::Device, device_desc: &libusb::DeviceDescriptor) -> Option<Endpoint> {
+    for n in 0..device_desc.num_configurations() {
+        let config_desc = match device.config_descriptor(n) {
+            Ok(c) => c,
+            Err(_) => continue
+        };
+
+        for interface in config_desc.interfaces() {
+            for interface_desc in interface.descriptors() {
+                for endpoint_desc in interface_desc.endpoint_descriptors() {
+                    if endpoint_desc.direction() == libusb::Direction::In
+                        && endpoint_desc.transfer_type() == libusb::TransferType::Bulk {
+                        return Some(Endpoint {
+                            config: config_desc.number(),
+                            iface: interface_desc.interface_number(),
+                            setting: interface_desc.setting_number(),
+                            address: endpoint_desc.address()
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    None
+}
+
+/// One class-07 printer device found while enumerating the bus, along with
+/// the descriptor strings needed to tell several attached printers apart.
+#[derive(Debug, Clone)]
+pub struct PrinterInfo {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub bus_number: u8,
+    pub address: u8,
+    pub manufacturer: Option<String>,
+    pub product: Option<String>,
+}
+
+fn find_device_by_vid_pid(
+    context: &mut libusb::Context,
+    vendor_id: u16,
+    product_id: u16,
+) -> Result<(libusb::Device, libusb::DeviceDescriptor), Error> {
+    for device in context.devices().map_err(Error::DeviceList)?.iter() {
+        let device_desc = match device.device_descriptor() {
+            Ok(d) => d,
+            Err(_) => continue
+        };
+
+        if device_desc.vendor_id() == vendor_id && device_desc.product_id() == product_id {
+            return Ok((device, device_desc));
+        }
+    }
+
+    Err(Error::NoDevice)
+}
+
+fn configure_endpoint(
+    handle: &mut libusb::DeviceHandle,
+    endpoint: &Endpoint,
+    auto_detach: bool,
+    detached_ifaces: &mut Vec<u8>,
+) -> Result<(), Error> {
+    if auto_detach && handle.kernel_driver_active(endpoint.iface).unwrap_or(false) {
+        handle.detach_kernel_driver(endpoint.iface).map_err(Error::DetachKernelDriver)?;
+        detached_ifaces.push(endpoint.iface);
+    }
+
+    handle.set_active_configuration(endpoint.config).map_err(Error::SetActiveConfig)?;
+    handle.claim_interface(endpoint.iface).map_err(Error::ClaimInterface)?;
+    handle.set_alternate_setting(endpoint.iface, endpoint.setting).map_err(Error::SetAlternateSetting)?;
     Ok(())
 }
 
 impl<'a> Usb<'a> {
-    pub fn new(context: &'a mut libusb::Context) -> Usb<'a> {
+    pub fn new(context: &'a mut libusb::Context) -> Result<Usb<'a>, Error> {
         let empty_stream : Vec<u8> = Vec::new();
 
-        match find_print_endpoint(context) {
-            Some((endpoint, vendor_id, product_id)) => {
-              let device_handle = context.open_device_with_vid_pid(vendor_id, product_id).unwrap();
-              return Usb {
-                  _vendor_id: Some(vendor_id),
-                  _product_id: Some(product_id),
-                  device_handle: Some(device_handle),
-                  write_endpoint: Some(endpoint),
-                  stream: empty_stream
-              }
-            },
-            None =>
-              return Usb {
-                  _vendor_id: None,
-                  _product_id: None,
-                  device_handle: None,
-                  write_endpoint: None,
-                  stream: empty_stream
-              }
+        let (device, device_desc) = find_print_device(context)?.ok_or(Error::NoDevice)?;
+        let (endpoint, vendor_id, product_id) = find_write_endpoint(&device, &device_desc)
+            .ok_or(Error::NoWriteEndpoint)?;
+        let read_endpoint = find_read_endpoint(&device, &device_desc);
+
+        let mut device_handle = device.open().map_err(Error::OpenDevice)?;
+        device_handle.reset().map_err(Error::Usb)?;
+        Ok(Usb {
+            _vendor_id: Some(vendor_id),
+            _product_id: Some(product_id),
+            device_handle: Some(device_handle),
+            write_endpoint: Some(endpoint),
+            read_endpoint,
+            auto_detach: true,
+            detached_ifaces: Vec::new(),
+            stream: empty_stream
+        })
+    }
+
+    /// Controls whether a bound kernel driver (e.g. usblp on Linux) is
+    /// detached before claiming the interface. Enabled by default.
+    pub fn set_auto_detach(&mut self, auto_detach: bool) {
+        self.auto_detach = auto_detach;
+    }
+
+    /// List every class-07 (printer) device currently attached, so a
+    /// multi-printer deployment can pick the right one deterministically.
+    pub fn list(context: &mut libusb::Context) -> Result<Vec<PrinterInfo>, Error> {
+        let mut printers = Vec::new();
+
+        for device in context.devices().map_err(Error::DeviceList)?.iter() {
+            let device_desc = match device.device_descriptor() {
+                Ok(d) => d,
+                Err(_) => continue
+            };
+
+            let is_printer = (0..device_desc.num_configurations()).any(|n| {
+                device.config_descriptor(n).map(|config_desc| {
+                    config_desc.interfaces().any(|interface| {
+                        interface.descriptors().any(|d| d.class_code() == 7)
+                    })
+                }).unwrap_or(false)
+            });
+
+            if !is_printer {
+                continue;
+            }
+
+            let (manufacturer, product) = match device.open() {
+                Ok(handle) => {
+                    let timeout = Duration::from_secs(1);
+                    let languages = handle.read_languages(timeout).unwrap_or_default();
+                    let language = languages.first().cloned();
+                    let manufacturer = language.and_then(|lang| {
+                        handle.read_manufacturer_string(lang, &device_desc, timeout).ok()
+                    });
+                    let product = language.and_then(|lang| {
+                        handle.read_product_string(lang, &device_desc, timeout).ok()
+                    });
+                    (manufacturer, product)
+                },
+                Err(_) => (None, None)
+            };
+
+            printers.push(PrinterInfo {
+                vendor_id: device_desc.vendor_id(),
+                product_id: device_desc.product_id(),
+                bus_number: device.bus_number(),
+                address: device.address(),
+                manufacturer,
+                product,
+            });
         }
+
+        Ok(printers)
+    }
+
+    /// Open the printer matching `vendor_id`/`product_id` exactly, instead
+    /// of grabbing the first class-07 device on the bus.
+    pub fn open(context: &'a mut libusb::Context, vendor_id: u16, product_id: u16) -> Result<Usb<'a>, Error> {
+        let empty_stream: Vec<u8> = Vec::new();
+        let (device, device_desc) = find_device_by_vid_pid(context, vendor_id, product_id)?;
+        let (endpoint, _, _) = find_write_endpoint(&device, &device_desc)
+            .ok_or(Error::NoWriteEndpoint)?;
+        let read_endpoint = find_read_endpoint(&device, &device_desc);
+
+        let mut device_handle = device.open().map_err(Error::OpenDevice)?;
+        device_handle.reset().map_err(Error::Usb)?;
+
+        Ok(Usb {
+            _vendor_id: Some(vendor_id),
+            _product_id: Some(product_id),
+            device_handle: Some(device_handle),
+            write_endpoint: Some(endpoint),
+            read_endpoint,
+            auto_detach: true,
+            detached_ifaces: Vec::new(),
+            stream: empty_stream
+        })
+    }
+
+    /// Ask the printer for its current real-time status over the USB IN
+    /// endpoint by sending `DLE EOT n` and decoding the single-byte reply.
+    pub fn query_status(&mut self, kind: StatusKind) -> Result<PrinterStatus, Error> {
+        let auto_detach = self.auto_detach;
+        let handle = self.device_handle.as_mut().ok_or(Error::NoDevice)?;
+        let write_endpoint = self.write_endpoint.as_ref().ok_or(Error::NoWriteEndpoint)?;
+        let read_endpoint = self.read_endpoint.as_ref().ok_or(Error::NoReadEndpoint)?;
+        let detached_ifaces = &mut self.detached_ifaces;
+
+        configure_endpoint(handle, write_endpoint, auto_detach, detached_ifaces)?;
+
+        let command = [0x10, 0x04, kind as u8];
+        handle
+            .write_bulk(write_endpoint.address, &command, Duration::from_secs(5))
+            .map_err(Error::Usb)?;
+
+        let mut buf = [0u8; 1];
+        handle
+            .read_bulk(read_endpoint.address, &mut buf, Duration::from_secs(5))
+            .map_err(Error::Usb)?;
+
+        Ok(PrinterStatus::decode(kind, buf[0]))
     }
 }
 
@@ -137,50 +444,77 @@ impl<'a> io::Write for Usb<'a> {
         let empty_stream : Vec<u8> = Vec::new();
         let device_handle = &mut self.device_handle;
         let write_endpoint = &mut self.write_endpoint;
+        let auto_detach = self.auto_detach;
+        let detached_ifaces = &mut self.detached_ifaces;
 
         match device_handle {
             Some(handle) => {
-                handle.reset().unwrap();
-
                 match write_endpoint {
-                    Some(endpoint) => match configure_endpoint(handle, endpoint) {
+                    Some(endpoint) => match configure_endpoint(handle, endpoint, auto_detach, detached_ifaces) {
                         Ok(_) => {
-                            match handle.write_bulk(endpoint.address, &self.stream.as_slice(), Duration::from_secs(10)) {
-                                Ok(n) => {
-                                  println!("already write {} bytes!", n);
-                                  self.stream = empty_stream;
-                                  Ok(())
-                                },
-                                Err(err) => {
-                                  println!("error happened! {:?}", err);
-                                  self.stream = empty_stream;
-                                  Err(std::io::Error::new(std::io::ErrorKind::Other, "oh no!"))
-                                }
-                            }
+                            let result = handle.write_bulk(endpoint.address, &self.stream.as_slice(), Duration::from_secs(10));
+                            self.stream = empty_stream;
+                            result.map(|_| ()).map_err(|err| Error::Usb(err).into())
                         },
                         Err(err) => {
-                            println!("error happened! {:?}", err);
                             self.stream = empty_stream;
-                            Err(std::io::Error::new(std::io::ErrorKind::Other, "oh no!"))
+                            Err(err.into())
                         }
                     },
                     None => {
-                        println!("didn't find a printer to do print jobs");
                         self.stream = empty_stream;
-                        Ok(())
+                        Err(Error::NoWriteEndpoint.into())
                     }
                 }
             },
             None => {
-                println!("didn't find a printer to do print jobs");
                 self.stream = empty_stream;
-                Ok(())
+                Err(Error::NoDevice.into())
             }
         }
     }
 }
 
-pub struct Serial {}
+impl<'a> Drop for Usb<'a> {
+    fn drop(&mut self) {
+        if let Some(handle) = self.device_handle.as_mut() {
+            for iface in self.detached_ifaces.drain(..) {
+                let _ = handle.release_interface(iface);
+                let _ = handle.attach_kernel_driver(iface);
+            }
+        }
+    }
+}
+
+/// Flow control mode for the `Serial` backend, mirroring `serialport::FlowControl`.
+pub type FlowControl = serialport::FlowControl;
+
+/// An ESC/POS printer connected over RS-232 or a USB-serial bridge, rather
+/// than the USB printer class that `Usb` targets.
+pub struct Serial {
+    port: Box<dyn serialport::SerialPort>,
+}
+
+impl Serial {
+    pub fn open(port: &str, baud: u32, flow: FlowControl) -> Result<Serial, Error> {
+        let port = serialport::new(port, baud)
+            .flow_control(flow)
+            .timeout(Duration::from_secs(10))
+            .open()
+            .map_err(|err| Error::Io(io::Error::new(io::ErrorKind::Other, err.to_string())))?;
+        Ok(Serial { port })
+    }
+}
+
+impl io::Write for Serial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
 
 #[derive(Debug)]
 pub struct Network {
@@ -190,13 +524,13 @@ pub struct Network {
 }
 
 impl Network {
-    pub fn new(host: &str, port: u16) -> Network {
-        let stream = net::TcpStream::connect((host, port)).unwrap();
-        Network {
+    pub fn new(host: &str, port: u16) -> Result<Network, Error> {
+        let stream = net::TcpStream::connect((host, port))?;
+        Ok(Network {
             _host: host.to_string(),
             _port: port,
             stream,
-        }
+        })
     }
 }
 
@@ -210,6 +544,157 @@ impl io::Write for Network {
     }
 }
 
+const USBIP_VERSION: u16 = 0x0111;
+const OP_REQ_IMPORT: u16 = 0x8003;
+const OP_REP_IMPORT: u16 = 0x0003;
+const USBIP_CMD_SUBMIT: u32 = 0x0000_0001;
+const USBIP_RET_SUBMIT: u32 = 0x0000_0003;
+const USBIP_DIR_OUT: u32 = 0;
+const BUSID_SIZE: usize = 32;
+
+/// Drives a printer exported by a `usbipd` server over TCP, speaking the
+/// USB/IP protocol directly so the printer can be used without a local
+/// kernel driver. Implements `io::Write` as a drop-in alternative to `Usb`.
+pub struct UsbIp {
+    stream: net::TcpStream,
+    devid: u32,
+    endpoint: u32,
+    seqnum: u32,
+    buf: Vec<u8>,
+}
+
+fn read_busid(reader: &mut impl io::Read) -> io::Result<String> {
+    let mut raw = [0u8; BUSID_SIZE];
+    reader.read_exact(&mut raw)?;
+    let end = raw.iter().position(|&b| b == 0).unwrap_or(BUSID_SIZE);
+    Ok(String::from_utf8_lossy(&raw[..end]).into_owned())
+}
+
+impl UsbIp {
+    /// Default USB/IP server port.
+    pub const DEFAULT_PORT: u16 = 3240;
+
+    /// Attach to the device identified by `busid` (e.g. `"1-1"`) exported by
+    /// the USB/IP server at `host:port`, submitting bulk transfers to
+    /// `endpoint`.
+    pub fn connect(host: &str, port: u16, busid: &str, endpoint: u8) -> Result<UsbIp, Error> {
+        let mut stream = net::TcpStream::connect((host, port))?;
+
+        let mut busid_buf = [0u8; BUSID_SIZE];
+        let busid_bytes = busid.as_bytes();
+        let len = busid_bytes.len().min(BUSID_SIZE);
+        busid_buf[..len].copy_from_slice(&busid_bytes[..len]);
+
+        stream.write_u16::<BigEndian>(USBIP_VERSION)?;
+        stream.write_u16::<BigEndian>(OP_REQ_IMPORT)?;
+        stream.write_u32::<BigEndian>(0)?;
+        stream.write_all(&busid_buf)?;
+        stream.flush()?;
+
+        let version = stream.read_u16::<BigEndian>()?;
+        let command = stream.read_u16::<BigEndian>()?;
+        let status = stream.read_u32::<BigEndian>()?;
+
+        if version != USBIP_VERSION || command != OP_REP_IMPORT {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected OP_REP_IMPORT header",
+            )));
+        }
+        if status != 0 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                format!("OP_REQ_IMPORT rejected with status {}", status),
+            )));
+        }
+
+        // usbip_usb_device is `path[256]` followed by `busid[32]` - read in
+        // that order, even though busnum/devnum land at the same offset
+        // either way (32 + 256 == 256 + 32).
+        let mut _path = [0u8; 256];
+        stream.read_exact(&mut _path)?;
+        let _exported_busid = read_busid(&mut stream)?;
+        let bus_num = stream.read_u32::<BigEndian>()?;
+        let dev_num = stream.read_u32::<BigEndian>()?;
+        let _speed = stream.read_u32::<BigEndian>()?;
+        let _vendor_id = stream.read_u16::<BigEndian>()?;
+        let _product_id = stream.read_u16::<BigEndian>()?;
+        let _bcd_device = stream.read_u16::<BigEndian>()?;
+        let _device_class = stream.read_u8()?;
+        let _device_subclass = stream.read_u8()?;
+        let _device_protocol = stream.read_u8()?;
+        let _configuration_value = stream.read_u8()?;
+        let _num_configurations = stream.read_u8()?;
+        let _num_interfaces = stream.read_u8()?;
+
+        Ok(UsbIp {
+            stream,
+            devid: (bus_num << 16) | dev_num,
+            endpoint: endpoint as u32,
+            seqnum: 0,
+            buf: Vec::new(),
+        })
+    }
+
+    fn submit(&mut self, payload: &[u8]) -> Result<(), Error> {
+        self.seqnum += 1;
+
+        self.stream.write_u32::<BigEndian>(USBIP_CMD_SUBMIT)?;
+        self.stream.write_u32::<BigEndian>(self.seqnum)?;
+        self.stream.write_u32::<BigEndian>(self.devid)?;
+        self.stream.write_u32::<BigEndian>(USBIP_DIR_OUT)?;
+        self.stream.write_u32::<BigEndian>(self.endpoint)?;
+        self.stream.write_u32::<BigEndian>(0)?; // transfer_flags
+        self.stream.write_u32::<BigEndian>(payload.len() as u32)?;
+        self.stream.write_i32::<BigEndian>(0)?; // start_frame
+        self.stream.write_i32::<BigEndian>(-1)?; // number_of_packets (not an isochronous transfer)
+        self.stream.write_i32::<BigEndian>(0)?; // interval
+        self.stream.write_all(&[0u8; 8])?; // setup, unused for bulk OUT
+        self.stream.write_all(payload)?;
+        self.stream.flush()?;
+
+        let command = self.stream.read_u32::<BigEndian>()?;
+        let seqnum = self.stream.read_u32::<BigEndian>()?;
+        let _devid = self.stream.read_u32::<BigEndian>()?;
+        let _direction = self.stream.read_u32::<BigEndian>()?;
+        let _endpoint = self.stream.read_u32::<BigEndian>()?;
+        let status = self.stream.read_i32::<BigEndian>()?;
+        let _actual_length = self.stream.read_u32::<BigEndian>()?;
+        let _start_frame = self.stream.read_i32::<BigEndian>()?;
+        let _number_of_packets = self.stream.read_i32::<BigEndian>()?;
+        let _error_count = self.stream.read_i32::<BigEndian>()?;
+        let mut _setup = [0u8; 8];
+        self.stream.read_exact(&mut _setup)?;
+
+        if command != USBIP_RET_SUBMIT || seqnum != self.seqnum {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "mismatched USBIP_RET_SUBMIT reply",
+            )));
+        }
+        if status != 0 {
+            return Err(Error::Io(io::Error::new(
+                io::ErrorKind::Other,
+                format!("USBIP_CMD_SUBMIT failed with status {}", status),
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+impl io::Write for UsbIp {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buf.extend(buf.iter().cloned());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let payload = std::mem::take(&mut self.buf);
+        self.submit(&payload).map_err(Into::into)
+    }
+}
+
 #[derive(Debug)]
 pub struct File<W> {
     fobj: W,
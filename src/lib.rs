@@ -2,6 +2,7 @@ extern crate byteorder;
 extern crate encoding;
 extern crate image;
 extern crate libusb;
+extern crate serialport;
 
 #[cfg(feature = "qrcode_builder")]
 extern crate qrcode;